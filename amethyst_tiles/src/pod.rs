@@ -1,5 +1,7 @@
 #![allow(clippy::default_trait_access)]
 //! GPU POD data types.
+use std::ops::Range;
+
 use amethyst_assets::{AssetStorage, Handle};
 use amethyst_core::math::Point3;
 use amethyst_rendy::{
@@ -8,11 +10,47 @@ use amethyst_rendy::{
         mesh::{AsVertex, VertexFormat},
     },
     resources::Tint as TintComponent,
-    sprite::SpriteSheet,
+    sprite::{SpriteSheet, TextureCoordinates},
     Texture,
 };
 use glsl_layout::*;
 
+/// Per-tile orientation, matching the `flipped-H` / `flipped-V` / `flipped-diagonal`
+/// triple that TMX/Tiled uses to reuse a single source tile for all eight orientations.
+///
+/// The flags are resolved into the sampled UV rectangle in [`TileArgs::from_data`], so no
+/// extra vertex attribute is required and the [`AsVertex`] layout is unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TileOrientation {
+    /// Exchange the left/right texels, mirroring the tile horizontally.
+    pub flip_horizontal: bool,
+    /// Exchange the top/bottom texels, mirroring the tile vertically.
+    pub flip_vertical: bool,
+    /// Swap the `u`/`v` pair so the sampler reads the tile transposed.
+    pub flip_diagonal: bool,
+}
+
+impl TileOrientation {
+    /// Resolves the sprite's texture coordinates into the `(u_offset, v_offset)` pair the
+    /// sampler should read for this orientation.
+    fn resolve(self, tex_coords: &TextureCoordinates) -> ([f32; 2], [f32; 2]) {
+        let mut u_offset = if self.flip_horizontal {
+            [tex_coords.right, tex_coords.left]
+        } else {
+            [tex_coords.left, tex_coords.right]
+        };
+        let mut v_offset = if self.flip_vertical {
+            [tex_coords.bottom, tex_coords.top]
+        } else {
+            [tex_coords.top, tex_coords.bottom]
+        };
+        if self.flip_diagonal {
+            std::mem::swap(&mut u_offset, &mut v_offset);
+        }
+        (u_offset, v_offset)
+    }
+}
+
 /// `TileMapArgs`
 /// ```glsl,ignore
 /// uniform TileMapArgs {
@@ -39,15 +77,14 @@ pub struct TileMapArgs {
 
 /// Tile Vertex Data
 /// ```glsl,ignore
-/// vec2 dir_x;
-/// vec2 dir_y;
-/// vec2 pos;
 /// vec2 u_offset;
 /// vec2 v_offset;
-/// float depth;
 /// vec4 tint;
+/// vec3 tile_coordinate;
+/// uint tex_index;
+/// float depth;
 /// ```
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, AsStd140)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, AsStd140)]
 #[repr(C, align(4))]
 pub struct TileArgs {
     /// Upper-left coordinate of the sprite in the spritesheet
@@ -58,6 +95,12 @@ pub struct TileArgs {
     pub tint: vec4,
     /// Tile coordinate
     pub tile_coordinate: vec3,
+    /// Index of the sprite sheet this tile samples from, naming the layer of the bound
+    /// texture array / descriptor array the fragment shader reads.
+    pub tex_index: uint,
+    /// Depth of this tile, taken from its z-coordinate / layer. Used to order transparent
+    /// tiles back-to-front so overlapping semi-transparent tiles blend correctly.
+    pub depth: float,
 }
 
 impl AsVertex for TileArgs {
@@ -67,6 +110,8 @@ impl AsVertex for TileArgs {
             (Format::Rg32Sfloat, "v_offset"),
             (Format::Rgba32Sfloat, "tint"),
             (Format::Rgb32Sfloat, "tile_coordinate"),
+            (Format::R32Uint, "tex_index"),
+            (Format::R32Sfloat, "depth"),
         ))
     }
 }
@@ -77,26 +122,33 @@ impl TileArgs {
     ///
     /// # Arguments
     /// * `tex_storage` - `Texture` Storage
-    /// * `sprite_storage` - `SpriteSheet` Storage
+    /// * `sprite_sheets` - the `SpriteSheet`s referenced by the map, one per texture-array layer
+    /// * `sheet_index` - index of the sheet this tile samples from, stored in `tex_index`
     /// * `sprite_render` - `SpriteRender` component reference
+    /// * `tint` - optional `Tint` component reference
+    /// * `orientation` - optional per-tile flip/rotation, `None` meaning unrotated
     /// * `transform` - 'Transform' component reference
     pub fn from_data<'a>(
         tex_storage: &AssetStorage<Texture>,
-        sprite_sheet: &'a SpriteSheet,
+        sprite_sheets: &'a [SpriteSheet],
+        sheet_index: usize,
         sprite_number: usize,
         tint: Option<&TintComponent>,
+        orientation: Option<TileOrientation>,
         tile_coordinate: &Point3<u32>,
     ) -> Option<(Self, &'a Handle<Texture>)> {
+        let sprite_sheet = &sprite_sheets[sheet_index];
         if !tex_storage.contains(&sprite_sheet.texture) {
             return None;
         }
 
         let sprite = &sprite_sheet.sprites[sprite_number];
+        let (u_offset, v_offset) = orientation.unwrap_or_default().resolve(&sprite.tex_coords);
 
         Some((
             Self {
-                u_offset: [sprite.tex_coords.left, sprite.tex_coords.right].into(),
-                v_offset: [sprite.tex_coords.top, sprite.tex_coords.bottom].into(),
+                u_offset: u_offset.into(),
+                v_offset: v_offset.into(),
                 tint: tint.map_or([1.0; 4].into(), |t| {
                     let (r, g, b, a) = t.0.into_components();
                     [r, g, b, a].into()
@@ -107,8 +159,340 @@ impl TileArgs {
                     tile_coordinate.z as f32,
                 ]
                 .into(),
+                tex_index: (sheet_index as u32).into(),
+                depth: (tile_coordinate.z as f32).into(),
             },
             &sprite_sheet.texture,
         ))
     }
 }
+
+/// Largest depth range [`depth_sorted_order`] will counting-sort before falling back to a
+/// comparison sort, bounding the temporary bucket allocation regardless of how far apart the
+/// tile layers are spread.
+const MAX_DEPTH_BUCKETS: usize = 1024;
+
+/// Orders `tiles` for a correctly-blended draw, returning the indices to emit into the vertex
+/// stream.
+///
+/// Opaque tiles (tint alpha `>= 1.0`) keep their buffer order and rely on the depth test, so
+/// they are emitted first, untouched. Transparent tiles (alpha `< 1.0`) follow, sorted
+/// back-to-front — largest [`depth`](TileArgs::depth) first — so overlapping semi-transparent
+/// tiles such as shadows, water, or fog layers blend in the right order.
+///
+/// For the small range of layer values a map typically uses, the transparent tiles are ordered
+/// with a counting sort bucketed by the quantized depth key, keeping the pass `O(n)` when a map
+/// has tens of thousands of tiles. When the depth range exceeds [`MAX_DEPTH_BUCKETS`] — a sparse
+/// map with a few tiles spread across distant layers — it falls back to a comparison sort so the
+/// bucket arrays never grow with the range rather than the tile count. Both paths are stable, so
+/// tiles sharing a depth keep their relative buffer order.
+pub fn depth_sorted_order(tiles: &[TileArgs]) -> Vec<usize> {
+    let is_transparent = |tile: &TileArgs| -> bool {
+        let tint: [f32; 4] = tile.tint.into();
+        tint[3] < 1.0
+    };
+    let depth_key = |index: usize| -> i64 {
+        let depth: f32 = tiles[index].depth.into();
+        depth.round() as i64
+    };
+
+    let mut order = Vec::with_capacity(tiles.len());
+    let mut transparent = Vec::new();
+    for (index, tile) in tiles.iter().enumerate() {
+        if is_transparent(tile) {
+            transparent.push(index);
+        } else {
+            order.push(index);
+        }
+    }
+    if transparent.is_empty() {
+        return order;
+    }
+
+    let min = transparent.iter().map(|&i| depth_key(i)).min().unwrap();
+    let max = transparent.iter().map(|&i| depth_key(i)).max().unwrap();
+
+    // A sparse depth range would make the bucket arrays scale with the range, not the tile
+    // count, so fall back to a stable comparison sort once it would exceed the bucket cap.
+    if max - min >= MAX_DEPTH_BUCKETS as i64 {
+        transparent.sort_by(|&a, &b| depth_key(b).cmp(&depth_key(a)));
+        order.extend_from_slice(&transparent);
+        return order;
+    }
+
+    let buckets = (max - min + 1) as usize;
+    let mut counts = vec![0_usize; buckets];
+    for &index in &transparent {
+        counts[(depth_key(index) - min) as usize] += 1;
+    }
+
+    // Lay the buckets out high-depth first so the emitted run is back-to-front.
+    let mut offsets = vec![0_usize; buckets];
+    let mut acc = 0;
+    for bucket in (0..buckets).rev() {
+        offsets[bucket] = acc;
+        acc += counts[bucket];
+    }
+
+    let base = order.len();
+    order.resize(tiles.len(), 0);
+    for &index in &transparent {
+        let bucket = (depth_key(index) - min) as usize;
+        order[base + offsets[bucket]] = index;
+        offsets[bucket] += 1;
+    }
+    order
+}
+
+/// Monotonic version counter for a single tile cell.
+///
+/// Bump it whenever a cell's visual inputs (sprite number, tint, or visibility) change; the
+/// [`DirtyTileBuffer`] compares this against the value captured at the last upload to decide
+/// whether the slot needs re-extracting.
+pub type TileGeneration = u64;
+
+/// Change-tracking layer over the packed `TileArgs` vertex buffer.
+///
+/// Re-running [`TileArgs::from_data`] for every visible tile each frame is wasteful for large,
+/// mostly-static maps where only a handful of cells change. This keeps the last-uploaded buffer
+/// alongside a per-slot generation snapshot and, on [`update`](Self::update), only rebuilds the
+/// slots whose generation differs. The staged writes are coalesced into the minimal dirty span,
+/// so untouched regions skip both the `from_data` call and the memcpy; the output buffer is
+/// byte-for-byte the same as a full re-extract.
+///
+/// Slots that have never been uploaded — a freshly constructed buffer, or cells newly revealed
+/// by scrolling into view — carry no snapshot and are therefore treated as dirty on first sight.
+#[derive(Clone, Debug, Default)]
+pub struct DirtyTileBuffer {
+    /// Last-uploaded vertex data, one slot per tile.
+    args: Vec<TileArgs>,
+    /// Generation captured when each slot was last rebuilt; `None` until first upload and
+    /// again after [`mark_all_dirty`](Self::mark_all_dirty).
+    versions: Vec<Option<TileGeneration>>,
+}
+
+impl DirtyTileBuffer {
+    /// Creates a buffer for `len` tiles, every slot unseen and therefore dirty.
+    pub fn new(len: usize) -> Self {
+        Self {
+            args: vec![TileArgs::default(); len],
+            versions: vec![None; len],
+        }
+    }
+
+    /// Number of tile slots tracked by this buffer.
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    /// Whether the buffer tracks no tiles.
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// The last-uploaded vertex data.
+    pub fn args(&self) -> &[TileArgs] {
+        &self.args
+    }
+
+    /// Grows or shrinks the buffer to `len` slots, marking any slot revealed by the growth as
+    /// dirty on first sight. Used when the visible window scrolls and exposes new tiles.
+    pub fn resize(&mut self, len: usize) {
+        self.args.resize(len, TileArgs::default());
+        self.versions.resize(len, None);
+    }
+
+    /// Forces every slot to be rebuilt on the next [`update`](Self::update). Call this when an
+    /// input shared by the whole map changes — a camera or spritesheet swap — and the cached
+    /// vertex data can no longer be trusted.
+    pub fn mark_all_dirty(&mut self) {
+        for version in &mut self.versions {
+            *version = None;
+        }
+    }
+
+    /// Reconciles the buffer against the `current` per-slot generations, rebuilding only the
+    /// slots whose generation differs from the last upload.
+    ///
+    /// `rebuild` is invoked exactly once per dirty slot to produce its fresh [`TileArgs`];
+    /// untouched slots are left as-is and never passed to it. Returns the coalesced range of
+    /// slots that changed — the span the caller needs to re-stage to the GPU — or `None` when
+    /// nothing was dirty.
+    pub fn update<F>(&mut self, current: &[TileGeneration], mut rebuild: F) -> Option<Range<usize>>
+    where
+        F: FnMut(usize) -> TileArgs,
+    {
+        debug_assert_eq!(current.len(), self.args.len());
+
+        let mut dirty: Option<Range<usize>> = None;
+        for (index, &generation) in current.iter().enumerate() {
+            if self.versions[index] == Some(generation) {
+                continue;
+            }
+            self.args[index] = rebuild(index);
+            self.versions[index] = Some(generation);
+            dirty = Some(match dirty {
+                Some(range) => range.start..index + 1,
+                None => index..index + 1,
+            });
+        }
+        dirty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amethyst_rendy::sprite::TextureCoordinates;
+
+    const COORDS: TextureCoordinates = TextureCoordinates {
+        left: 0.1,
+        right: 0.2,
+        top: 0.3,
+        bottom: 0.4,
+    };
+
+    fn orientation(flip_horizontal: bool, flip_vertical: bool, flip_diagonal: bool) -> TileOrientation {
+        TileOrientation {
+            flip_horizontal,
+            flip_vertical,
+            flip_diagonal,
+        }
+    }
+
+    #[test]
+    fn resolve_covers_all_eight_orientations() {
+        // One source tile reused for every TMX orientation: left/right=[0.1, 0.2],
+        // top/bottom=[0.3, 0.4]. H mirrors u, V mirrors v, D transposes the pair.
+        let cases = [
+            ((false, false, false), ([0.1, 0.2], [0.3, 0.4])),
+            ((true, false, false), ([0.2, 0.1], [0.3, 0.4])),
+            ((false, true, false), ([0.1, 0.2], [0.4, 0.3])),
+            ((true, true, false), ([0.2, 0.1], [0.4, 0.3])),
+            ((false, false, true), ([0.3, 0.4], [0.1, 0.2])),
+            ((true, false, true), ([0.3, 0.4], [0.2, 0.1])),
+            ((false, true, true), ([0.4, 0.3], [0.1, 0.2])),
+            ((true, true, true), ([0.4, 0.3], [0.2, 0.1])),
+        ];
+        for ((h, v, d), expected) in cases {
+            assert_eq!(orientation(h, v, d).resolve(&COORDS), expected);
+        }
+    }
+
+    #[test]
+    fn resolve_default_is_unrotated() {
+        assert_eq!(
+            TileOrientation::default().resolve(&COORDS),
+            ([0.1, 0.2], [0.3, 0.4])
+        );
+    }
+
+    fn tile(depth: f32, alpha: f32) -> TileArgs {
+        TileArgs {
+            tint: [1.0, 1.0, 1.0, alpha].into(),
+            depth: depth.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn depth_sort_leaves_opaque_only_maps_untouched() {
+        let tiles = [tile(5.0, 1.0), tile(1.0, 1.0), tile(3.0, 1.0)];
+        assert_eq!(depth_sorted_order(&tiles), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn depth_sort_orders_transparent_back_to_front_after_opaque() {
+        // indices 1 and 3 are opaque (kept in order, emitted first); the transparent
+        // 0, 2, 4 follow sorted by decreasing depth.
+        let tiles = [
+            tile(2.0, 0.5),
+            tile(9.0, 1.0),
+            tile(5.0, 0.5),
+            tile(8.0, 1.0),
+            tile(1.0, 0.5),
+        ];
+        assert_eq!(depth_sorted_order(&tiles), vec![1, 3, 2, 0, 4]);
+    }
+
+    #[test]
+    fn depth_sort_is_stable_on_ties() {
+        // All transparent, depths 1, 1, 2, 2 — farthest bucket first, buffer order within.
+        let tiles = [tile(1.0, 0.5), tile(1.0, 0.5), tile(2.0, 0.5), tile(2.0, 0.5)];
+        assert_eq!(depth_sorted_order(&tiles), vec![2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn depth_sort_falls_back_for_sparse_ranges() {
+        // A range wider than MAX_DEPTH_BUCKETS takes the comparison-sort path but must
+        // still order back-to-front and stay stable on ties.
+        let far = MAX_DEPTH_BUCKETS as f32 + 10.0;
+        let tiles = [tile(0.0, 0.5), tile(far, 0.5), tile(0.0, 0.5)];
+        assert_eq!(depth_sorted_order(&tiles), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn update_rebuilds_only_dirty_slots_and_coalesces_the_span() {
+        let mut buffer = DirtyTileBuffer::new(5);
+        // First sight: every slot is dirty, so the span covers the whole buffer.
+        let mut built = Vec::new();
+        let dirty = buffer.update(&[0, 0, 0, 0, 0], |i| {
+            built.push(i);
+            tile(i as f32, 1.0)
+        });
+        assert_eq!(dirty, Some(0..5));
+        assert_eq!(built, vec![0, 1, 2, 3, 4]);
+
+        // Nothing changed: no rebuilds, no dirty span.
+        built.clear();
+        let dirty = buffer.update(&[0, 0, 0, 0, 0], |i| {
+            built.push(i);
+            tile(i as f32, 1.0)
+        });
+        assert_eq!(dirty, None);
+        assert!(built.is_empty());
+
+        // Scattered slots 1 and 3 bump their generation; only they rebuild, but the
+        // returned span is coalesced to the enclosing 1..4 range.
+        built.clear();
+        let dirty = buffer.update(&[0, 1, 0, 1, 0], |i| {
+            built.push(i);
+            tile(i as f32, 1.0)
+        });
+        assert_eq!(dirty, Some(1..4));
+        assert_eq!(built, vec![1, 3]);
+    }
+
+    #[test]
+    fn mark_all_dirty_forces_a_full_rebuild() {
+        let mut buffer = DirtyTileBuffer::new(3);
+        buffer.update(&[7, 7, 7], |i| tile(i as f32, 1.0));
+        assert_eq!(buffer.update(&[7, 7, 7], |i| tile(i as f32, 1.0)), None);
+
+        buffer.mark_all_dirty();
+        let mut built = Vec::new();
+        let dirty = buffer.update(&[7, 7, 7], |i| {
+            built.push(i);
+            tile(i as f32, 1.0)
+        });
+        assert_eq!(dirty, Some(0..3));
+        assert_eq!(built, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn resize_reveals_new_slots_as_dirty() {
+        let mut buffer = DirtyTileBuffer::new(2);
+        buffer.update(&[0, 0], |i| tile(i as f32, 1.0));
+
+        buffer.resize(4);
+        assert_eq!(buffer.len(), 4);
+        // Slots 0 and 1 are unchanged; the revealed 2 and 3 rebuild on first sight.
+        let mut built = Vec::new();
+        let dirty = buffer.update(&[0, 0, 0, 0], |i| {
+            built.push(i);
+            tile(i as f32, 1.0)
+        });
+        assert_eq!(dirty, Some(2..4));
+        assert_eq!(built, vec![2, 3]);
+    }
+}